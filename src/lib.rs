@@ -26,6 +26,8 @@ extern crate tokio_core;
 extern crate tokio_io;
 
 use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
 
 use futures::{Poll, Future, Async};
 use native_tls::{HandshakeError, Error, TlsConnector, TlsAcceptor};
@@ -42,9 +44,40 @@ pub mod proto;
 /// and both the server and the client are ready for receiving and sending
 /// data. Bytes read from a `TlsStream` are decrypted from `S` and bytes written
 /// to a `TlsStream` are encrypted when passing through to `S`.
+///
+/// Once any I/O operation on a `TlsStream` fails with a fatal error (such as
+/// a TLS alert from the peer) the stream is considered broken, and all further
+/// reads, writes, flushes and shutdowns will fail with an error of kind
+/// `io::ErrorKind::Other` rather than touching the TLS session again.
+///
+/// Shutting a `TlsStream` down through `AsyncWrite::shutdown` only closes this
+/// side of the session: it sends our close_notify alert and then shuts down
+/// `S`, but it does not wait for the peer's close_notify. To see the peer
+/// close its side, keep reading until a read returns `Ok(0)`. This works
+/// whether the peer's close_notify arrives before or after ours is sent.
 #[derive(Debug)]
 pub struct TlsStream<S> {
     inner: native_tls::TlsStream<S>,
+    broken: bool,
+    eof: bool,
+    closing: bool,
+    shutdown: bool,
+    timings: HandshakeTimings,
+}
+
+/// How long a completed handshake took, as observed by the handshake futures
+/// of this crate.
+///
+/// The backends in `native-tls` drive the handshake internally and don't
+/// reveal individual handshake messages, so points such as the arrival of the
+/// ServerHello can't be measured. Only two spans are recorded: the initial
+/// `connect` or `accept` call, and the handshake as a whole.
+///
+/// This is returned from `TlsStream::handshake_timings`.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeTimings {
+    initial: Duration,
+    total: Duration,
 }
 
 /// Future returned from `TlsConnectorExt::connect_async` which will resolve
@@ -61,6 +94,9 @@ pub struct AcceptAsync<S> {
 
 struct MidHandshake<S> {
     inner: Option<Result<native_tls::TlsStream<S>, HandshakeError<S>>>,
+    started: Instant,
+    initial: Duration,
+    total: Option<Duration>,
 }
 
 /// Extension trait for the `TlsConnector` type in the `native_tls` crate.
@@ -131,11 +167,45 @@ pub trait TlsAcceptorExt: sealed::Sealed {
         where S: Read + Write; // TODO: change to AsyncRead + AsyncWrite
 }
 
+/// Underlying streams which are connected to a remote socket address.
+///
+/// This is used by `TlsStream::peer_addr` to reach through to the address of
+/// the remote end of the connection, and is implemented for the TCP streams
+/// of both the standard library and `tokio-core`.
+pub trait HasPeerAddr {
+    /// Returns the socket address of the remote peer of this stream.
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl HasPeerAddr for std::net::TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        std::net::TcpStream::peer_addr(self)
+    }
+}
+
+impl HasPeerAddr for tokio_core::net::TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        tokio_core::net::TcpStream::peer_addr(self)
+    }
+}
+
 mod sealed {
     pub trait Sealed {}
 }
 
 impl<S> TlsStream<S> {
+    fn new(inner: native_tls::TlsStream<S>,
+           timings: HandshakeTimings) -> TlsStream<S> {
+        TlsStream {
+            inner: inner,
+            broken: false,
+            eof: false,
+            closing: false,
+            shutdown: false,
+            timings: timings,
+        }
+    }
+
     /// Get access to the internal `native_tls::TlsStream` stream which also
     /// transitively allows access to `S`.
     pub fn get_ref(&self) -> &native_tls::TlsStream<S> {
@@ -147,21 +217,91 @@ impl<S> TlsStream<S> {
     pub fn get_mut(&mut self) -> &mut native_tls::TlsStream<S> {
         &mut self.inner
     }
+
+    /// Returns whether this stream has previously encountered a fatal error.
+    ///
+    /// A broken stream fails all further I/O; see the type-level
+    /// documentation for details.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    /// Returns whether this stream looks healthy enough to be reused, for
+    /// example when checking a connection back out of a pool.
+    ///
+    /// This returns `false` if the stream is broken, if a read has observed
+    /// the peer closing the session, or if `shutdown` has been called on this
+    /// side, even if it hasn't completed yet.
+    ///
+    /// Note that this only reflects what previous reads and writes have seen.
+    /// In particular, if the peer closes a connection which is sitting idle
+    /// in a pool, this keeps returning `true` until the close_notify or EOF
+    /// is picked up by a read. The same goes for renegotiations and alerts
+    /// which haven't been read yet.
+    pub fn is_reusable(&self) -> bool {
+        !self.broken && !self.eof && !self.closing
+    }
+
+    /// Returns how long the phases of the handshake which created this stream
+    /// took.
+    pub fn handshake_timings(&self) -> HandshakeTimings {
+        self.timings
+    }
+
+    fn check_broken(&self) -> io::Result<()> {
+        if self.broken {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               "TLS stream is broken after a fatal error"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn track<T>(&mut self, res: io::Result<T>) -> io::Result<T> {
+        if let Err(ref e) = res {
+            match e.kind() {
+                io::ErrorKind::WouldBlock |
+                io::ErrorKind::Interrupted |
+                io::ErrorKind::TimedOut => {}
+                _ => self.broken = true,
+            }
+        }
+        res
+    }
+}
+
+impl<S: HasPeerAddr> TlsStream<S> {
+    /// Returns the socket address of the remote peer of the underlying
+    /// stream.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.get_ref().peer_addr()
+    }
 }
 
 impl<S: Read + Write> Read for TlsStream<S> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        self.inner.read(buf)
+        try!(self.check_broken());
+        let res = self.inner.read(buf);
+        if let Ok(0) = res {
+            if !buf.is_empty() {
+                self.eof = true;
+            }
+        }
+        self.track(res)
     }
 }
 
 impl<S: Read + Write> Write for TlsStream<S> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.inner.write(buf)
+        try!(self.check_broken());
+        let res = self.inner.write(buf);
+        self.track(res)
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        self.inner.flush()
+        try!(self.check_broken());
+        let res = self.inner.flush();
+        self.track(res)
     }
 }
 
@@ -174,8 +314,20 @@ impl<S: AsyncRead + AsyncWrite> AsyncRead for TlsStream<S> {
 
 impl<S: AsyncRead + AsyncWrite> AsyncWrite for TlsStream<S> {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
-        try_nb!(self.inner.shutdown());
-        self.inner.get_mut().shutdown()
+        try!(self.check_broken());
+        self.closing = true;
+        // This only sends our close_notify, see the docs on `TlsStream`.
+        //
+        // Once our close_notify is out the door we only wait on the
+        // underlying stream, otherwise the TLS shutdown would be driven again
+        // each time `S` isn't ready yet.
+        if !self.shutdown {
+            let res = self.inner.shutdown();
+            try_nb!(self.track(res));
+            self.shutdown = true;
+        }
+        let res = self.inner.get_mut().shutdown();
+        self.track(res)
     }
 }
 
@@ -184,9 +336,7 @@ impl TlsConnectorExt for TlsConnector {
         where S: Read + Write,
     {
         ConnectAsync {
-            inner: MidHandshake {
-                inner: Some(self.connect(domain, stream)),
-            },
+            inner: MidHandshake::start(|| self.connect(domain, stream)),
         }
     }
 
@@ -195,9 +345,9 @@ impl TlsConnectorExt for TlsConnector {
         where S: Read + Write,
     {
         ConnectAsync {
-            inner: MidHandshake {
-                inner: Some(self.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)),
-            },
+            inner: MidHandshake::start(|| {
+                self.danger_connect_without_providing_domain_for_certificate_verification_and_server_name_indication(stream)
+            }),
         }
     }
 }
@@ -209,9 +359,7 @@ impl TlsAcceptorExt for TlsAcceptor {
         where S: Read + Write,
     {
         AcceptAsync {
-            inner: MidHandshake {
-                inner: Some(self.accept(stream)),
-            },
+            inner: MidHandshake::start(|| self.accept(stream)),
         }
     }
 }
@@ -238,6 +386,48 @@ impl<S: Read + Write> Future for AcceptAsync<S> {
     }
 }
 
+impl HandshakeTimings {
+    /// Returns the time spent in the initial call to `connect` or `accept`,
+    /// which writes the first flight of the handshake and processes whatever
+    /// the underlying stream has available at that point.
+    pub fn initial(&self) -> Duration {
+        self.initial
+    }
+
+    /// Returns the time from the start of the handshake until the backend
+    /// reported it complete, including any time spent waiting on the
+    /// underlying stream.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+}
+
+impl<S> MidHandshake<S> {
+    fn start<F>(f: F) -> MidHandshake<S>
+        where F: FnOnce() -> Result<native_tls::TlsStream<S>, HandshakeError<S>>,
+    {
+        let started = Instant::now();
+        let inner = f();
+        let initial = started.elapsed();
+        // If the handshake finished right away then so has our measurement,
+        // as the future itself may not be polled for a while yet.
+        let total = if inner.is_ok() { Some(initial) } else { None };
+        MidHandshake {
+            inner: Some(inner),
+            started: started,
+            initial: initial,
+            total: total,
+        }
+    }
+
+    fn finish(&self, stream: native_tls::TlsStream<S>) -> TlsStream<S> {
+        TlsStream::new(stream, HandshakeTimings {
+            initial: self.initial,
+            total: self.total.unwrap_or_else(|| self.started.elapsed()),
+        })
+    }
+}
+
 // TODO: change this to AsyncRead/AsyncWrite on next major version
 impl<S: Read + Write> Future for MidHandshake<S> {
     type Item = TlsStream<S>;
@@ -245,11 +435,11 @@ impl<S: Read + Write> Future for MidHandshake<S> {
 
     fn poll(&mut self) -> Poll<TlsStream<S>, Error> {
         match self.inner.take().expect("cannot poll MidHandshake twice") {
-            Ok(stream) => Ok(TlsStream { inner: stream }.into()),
+            Ok(stream) => Ok(self.finish(stream).into()),
             Err(HandshakeError::Failure(e)) => Err(e),
             Err(HandshakeError::Interrupted(s)) => {
                 match s.handshake() {
-                    Ok(stream) => Ok(TlsStream { inner: stream }.into()),
+                    Ok(stream) => Ok(self.finish(stream).into()),
                     Err(HandshakeError::Failure(e)) => Err(e),
                     Err(HandshakeError::Interrupted(s)) => {
                         self.inner = Some(Err(HandshakeError::Interrupted(s)));
@@ -10,14 +10,18 @@ extern crate cfg_if;
 
 use std::io::{self, Read, Write};
 use std::process::Command;
+use std::thread;
+use std::time::Duration;
 
-use futures::{Future, Poll};
+use futures::{Async, Future, Poll};
+use futures::future;
+use futures::task;
 use futures::stream::Stream;
 use tokio_io::{AsyncRead, AsyncWrite};
-use tokio_io::io::{read_to_end, copy, shutdown};
+use tokio_io::io::{read_to_end, copy, shutdown, write_all};
 use tokio_core::reactor::Core;
 use tokio_core::net::{TcpListener, TcpStream};
-use tokio_tls::{TlsConnectorExt, TlsAcceptorExt};
+use tokio_tls::{TlsConnectorExt, TlsAcceptorExt, TlsStream};
 use native_tls::{TlsConnector, TlsAcceptor, Pkcs12};
 
 macro_rules! t {
@@ -648,3 +652,320 @@ fn one_byte_at_a_time() {
     assert_eq!(amt, AMT);
     assert!(data == vec![9; amt as usize]);
 }
+
+// Runs a handshake over a fresh local TCP connection and hands back the
+// server and client ends. The client's socket is passed through `wrap` first
+// so tests can interpose on its I/O.
+fn connected_pair_with<C, F>(l: &mut Core, wrap: F)
+                             -> (TlsStream<TcpStream>, TlsStream<C>)
+    where F: FnOnce(TcpStream) -> C,
+          C: Read + Write,
+{
+    let srv = t!(TcpListener::bind(&t!("127.0.0.1:0".parse()), &l.handle()));
+    let addr = t!(srv.local_addr());
+
+    let (server_cx, client_cx) = contexts();
+
+    let socket = srv.incoming().take(1).collect();
+    let accepted = socket.map(|mut socket| {
+        socket.remove(0).0
+    }).and_then(move |socket| {
+        server_cx.accept_async(socket).map_err(native2io)
+    });
+
+    let client = TcpStream::connect(&addr, &l.handle());
+    let connected = client.and_then(move |socket| {
+        client_cx.connect_async("localhost", wrap(socket)).map_err(native2io)
+    });
+
+    t!(l.run(accepted.join(connected)))
+}
+
+fn connected_pair(l: &mut Core)
+                  -> (TlsStream<TcpStream>, TlsStream<TcpStream>) {
+    connected_pair_with(l, |socket| socket)
+}
+
+#[test]
+fn peer_addr() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, client) = connected_pair(&mut l);
+    let server_addr = t!(server.get_ref().get_ref().local_addr());
+    let client_addr = t!(client.get_ref().get_ref().local_addr());
+    assert_eq!(t!(client.peer_addr()), server_addr);
+    assert_eq!(t!(server.peer_addr()), client_addr);
+}
+
+// Checks that a stream which hit a fatal error fails everything from then on
+// in the same way.
+fn assert_broken<S: AsyncRead + AsyncWrite>(stream: &mut TlsStream<S>) {
+    assert!(stream.is_broken());
+    assert!(!stream.is_reusable());
+
+    let mut buf = [0; 16];
+    let err = stream.read(&mut buf).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let err = stream.write(b"hello").unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let err = stream.flush().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+    let err = stream.shutdown().unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Other);
+}
+
+#[test]
+fn broken_after_failed_read() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (mut server, mut client) = connected_pair(&mut l);
+
+    // Write garbage straight to the underlying socket, bypassing TLS, which
+    // the client will fail to decode as a record.
+    t!(server.get_mut().get_mut().write_all(&[0xff; 64]));
+
+    let mut buf = [0; 16];
+    let err = l.run(future::poll_fn(|| {
+        match client.read(&mut buf) {
+            Ok(n) => Ok(Async::Ready(n)),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Ok(Async::NotReady)
+            }
+            Err(e) => Err(e),
+        }
+    })).unwrap_err();
+    assert!(err.kind() != io::ErrorKind::WouldBlock);
+    assert_broken(&mut client);
+}
+
+#[test]
+fn broken_after_failed_write() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, mut client) = connected_pair(&mut l);
+
+    // Close the peer's socket so the connection gets reset underneath the
+    // client, and keep writing until that surfaces as an error.
+    drop(server);
+
+    let err = l.run(future::poll_fn(|| -> Poll<(), io::Error> {
+        loop {
+            match client.write(&[9; 1024]) {
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    })).unwrap_err();
+    assert!(err.kind() != io::ErrorKind::WouldBlock);
+    assert_broken(&mut client);
+}
+
+// Sleeps for `delay` the first time a read actually returns data. The server
+// can't respond while the client is still inside its initial `connect` call,
+// so this always lands in a later round of the handshake.
+struct SlowRead<S> {
+    inner: S,
+    delay: Option<Duration>,
+}
+
+impl<S: Read> Read for SlowRead<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        if n > 0 {
+            if let Some(delay) = self.delay.take() {
+                thread::sleep(delay);
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for SlowRead<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn handshake_timings() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let delay = Duration::from_millis(100);
+    let (_server, client) = connected_pair_with(&mut l, |socket| {
+        SlowRead { inner: socket, delay: Some(delay) }
+    });
+    assert!(client.get_ref().get_ref().delay.is_none());
+
+    let timings = client.handshake_timings();
+    assert!(timings.initial() < delay);
+    assert!(timings.total() >= delay);
+}
+
+struct StallShutdown<S> {
+    inner: S,
+    stalls: usize,
+    writes: usize,
+}
+
+impl<S: Read> Read for StallShutdown<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<S: Write> Write for StallShutdown<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.writes += 1;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for StallShutdown<S> {}
+impl<S: AsyncWrite> AsyncWrite for StallShutdown<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if self.stalls > 0 {
+            self.stalls -= 1;
+            task::current().notify();
+            return Ok(Async::NotReady)
+        }
+        self.inner.shutdown()
+    }
+}
+
+#[test]
+fn shutdown_would_block() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, mut client) = connected_pair_with(&mut l, |socket| {
+        StallShutdown { inner: socket, stalls: 3, writes: 0 }
+    });
+    let writes = client.get_ref().get_ref().writes;
+
+    // A shutdown which is still in progress already rules out reuse
+    let stalled = t!(l.run(future::lazy(|| client.shutdown())));
+    assert!(stalled.is_not_ready());
+    assert!(!client.is_reusable());
+
+    let received = read_to_end(server, Vec::new());
+    let sent = shutdown(client);
+
+    let (client, (_, data)) = t!(l.run(sent.join(received)));
+    let stream = client.get_ref().get_ref();
+    assert_eq!(stream.stalls, 0);
+    // Only the one close_notify should have been written
+    assert_eq!(stream.writes, writes + 1);
+    assert!(data.is_empty());
+}
+
+#[test]
+fn reusable() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, client) = connected_pair(&mut l);
+    assert!(server.is_reusable());
+    assert!(client.is_reusable());
+
+    // Once the server shuts down neither side should be reused
+    let closed = shutdown(server).and_then(|server| {
+        read_to_end(client, Vec::new()).map(move |(client, data)| {
+            (server, client, data)
+        })
+    });
+    let (server, client, data) = t!(l.run(closed));
+    assert!(data.is_empty());
+    assert!(!server.is_reusable());
+    assert!(!client.is_reusable());
+    assert!(!client.is_broken());
+}
+
+#[test]
+fn small_read_buffer() {
+    // The largest plaintext a single TLS record can carry
+    const AMT: usize = 16 * 1024;
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, mut client) = connected_pair(&mut l);
+
+    // Write everything in one go so it goes out as a single record, then
+    // read it back with a buffer that can only ever hold one byte.
+    let sent = write_all(server, vec![9; AMT]).and_then(|(server, _)| {
+        shutdown(server)
+    });
+    let mut data = Vec::new();
+    let mut byte = [0; 1];
+    let received = future::poll_fn(|| loop {
+        match client.read(&mut byte) {
+            Ok(0) => return Ok(Async::Ready(())),
+            Ok(n) => {
+                assert_eq!(n, 1);
+                data.push(byte[0]);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Ok(Async::NotReady)
+            }
+            Err(e) => return Err(e),
+        }
+    });
+
+    t!(l.run(sent.join(received)));
+    assert!(data == vec![9; AMT]);
+}
+
+#[test]
+fn close_notify_race() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, client) = connected_pair(&mut l);
+
+    // Both sides send their close_notify before seeing the other's, and then
+    // wait for the peer's to arrive.
+    let server = shutdown(server).and_then(|s| read_to_end(s, Vec::new()));
+    let client = shutdown(client).and_then(|s| read_to_end(s, Vec::new()));
+
+    let ((server, a), (client, b)) = t!(l.run(server.join(client)));
+    assert!(a.is_empty());
+    assert!(b.is_empty());
+    assert!(!server.is_broken());
+    assert!(!client.is_broken());
+}
+
+#[test]
+fn close_notify_after_peer() {
+    drop(env_logger::init());
+    let mut l = t!(Core::new());
+
+    let (server, client) = connected_pair(&mut l);
+
+    // The server closes first, and the client only responds once it has
+    // seen the server's close_notify.
+    let server = shutdown(server).and_then(|s| read_to_end(s, Vec::new()));
+    let client = read_to_end(client, Vec::new()).and_then(|(s, data)| {
+        shutdown(s).map(move |s| (s, data))
+    });
+
+    let ((server, a), (client, b)) = t!(l.run(server.join(client)));
+    assert!(a.is_empty());
+    assert!(b.is_empty());
+    assert!(!server.is_broken());
+    assert!(!client.is_broken());
+}